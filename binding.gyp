@@ -14,7 +14,10 @@
       "include_dirs": [
         "<!@(node -p \"require('node-addon-api').include\")"
       ],
-      "libraries": [],
+      "libraries": [
+        "ntdll.lib",
+        "FirewallAPI.lib"
+      ],
       "dependencies": [
         "<!(node -p \"require('node-addon-api').gyp\")"
       ],
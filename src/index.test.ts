@@ -1,4 +1,185 @@
 import * as assert from "assert";
-import { getAppContainerProcessTokens } from "./index";
+import { spawn } from "child_process";
+import {
+  decodeObjectPath,
+  findAppContainerByPackageFamily,
+  formatPipePath,
+  getAppContainerProcessTokens,
+  getAppContainerTokenForSid,
+  getAppContainerTokenMap,
+  getAppContainerTokensBySession,
+  getAppContainerTokensDetailed,
+  getAppContainerTokensWithCapabilities,
+  getAppContainerTokensWithInfo,
+  getCurrentProcessAppContainerSid,
+  getCurrentSessionId,
+  getOpenHandleCount,
+  getOpenSnapshotCount,
+  getProcessCount,
+  getProcessCreationTime,
+  getProcessExitCode,
+  getProcessLogonId,
+  getProcessesCreatedSince,
+  getSandboxedProcesses,
+  isCurrentProcessAppContainer,
+  isProcessFrozen,
+  probeEnumerationCapabilities,
+  queryProcessTokenInfo,
+  terminateProcess,
+  waitForAppContainerProcess,
+  waitForProcessExit,
+  whichAreAppContainers,
+} from "./index";
+
+assert.strictEqual(
+  formatPipePath(3, "AppContainerNamedObjects\\S-1-15-2-1"),
+  "\\\\.\\pipe\\Sessions\\3\\AppContainerNamedObjects\\S-1-15-2-1"
+);
+assert.throws(() => formatPipePath(0, ""), RangeError);
+assert.strictEqual(
+  formatPipePath(1, "Sessions\\2\\AppContainerNamedObjects\\S-1-15-2-1"),
+  "\\\\.\\pipe\\Sessions\\2\\AppContainerNamedObjects\\S-1-15-2-1"
+);
+assert.strictEqual(
+  formatPipePath(1, "\\Sessions\\2\\AppContainerNamedObjects\\S-1-15-2-1"),
+  "\\\\.\\pipe\\Sessions\\2\\AppContainerNamedObjects\\S-1-15-2-1"
+);
+
+// A path that exactly fills the buffer has no null terminator.
+const unterminated = Uint16Array.from("AppContainerNamedObjects", (c) => c.charCodeAt(0));
+assert.strictEqual(decodeObjectPath(unterminated, unterminated.length), "AppContainerNamedObjects");
+assert.strictEqual(decodeObjectPath(unterminated, 3), "App");
+assert.strictEqual(decodeObjectPath(Uint16Array.of(0x41, 0, 0x42), 3), "A");
 
 assert(getAppContainerProcessTokens("Hello") instanceof Array);
+for (const { sessionId, tokens } of getAppContainerTokensBySession("Hello")!) {
+  assert(tokens.length > 0);
+  tokens.forEach((token) => assert(token.includes(`\\Sessions\\${sessionId}\\`)));
+}
+assert.strictEqual(typeof getAppContainerTokensDetailed("Hello")!.retried, "boolean");
+for (const { pid, reason, win32Error } of getAppContainerTokensDetailed("Hello")!.skipped) {
+  assert(typeof pid === "number" && reason.length > 0 && win32Error > 0);
+}
+const { inaccessible } = getAppContainerTokensDetailed("Hello")!;
+assert(!inaccessible.some(({ pid }) => pid === process.pid));
+for (const { kind, win32Error } of inaccessible) {
+  assert(["access_denied", "not_found", "other"].includes(kind) && win32Error > 0);
+}
+for (const p of getAppContainerTokensDetailed("Hello", { isProtected: true })!.inaccessible) {
+  assert(p.isProtected === undefined || p.kind === "access_denied");
+}
+// The detail checks below can only check something while app container
+// processes are running. This process isn't one, so it's never listed, and
+// details that weren't requested are never set.
+const withoutInfo = getAppContainerTokensWithInfo("Hello", {})!;
+assert(!withoutInfo.some(({ pid }) => pid === process.pid));
+if (withoutInfo.length === 0) {
+  console.log("No app container processes are running, skipping checks on their details");
+}
+for (const info of withoutInfo) {
+  assert.deepStrictEqual(Object.keys(info).sort(), ["pid", "token"]);
+}
+for (const { capabilities } of getAppContainerTokensWithCapabilities("Hello")!) {
+  capabilities.forEach((sid) => assert(sid.startsWith("S-1-15-3-")));
+}
+for (const { token, pid, aumid } of getAppContainerTokensWithInfo("Hello", { aumid: true })!) {
+  assert(token.endsWith("Hello") && typeof pid === "number");
+  assert(aumid === undefined || aumid.includes("!"));
+}
+for (const info of getAppContainerTokensWithInfo("Hello", { isBackgroundTask: true })!) {
+  assert.strictEqual(typeof info.isBackgroundTask, "boolean");
+}
+for (const { moniker } of getAppContainerTokensWithInfo("Hello", { moniker: true })!) {
+  assert(moniker === undefined || moniker.length > 0);
+}
+for (const info of getAppContainerTokensWithInfo("Hello", { loopbackExempt: true })!) {
+  assert.strictEqual(typeof info.loopbackExempt, "boolean");
+}
+const withMainPids = getAppContainerTokensWithInfo("Hello", { packageMainPid: true })!;
+for (const { packageMainPid } of withMainPids) {
+  assert(packageMainPid === undefined || withMainPids.some((p) => p.pid === packageMainPid));
+}
+for (const { ownerSid } of getAppContainerTokensWithInfo("Hello", { ownerSid: true })!) {
+  assert(ownerSid!.startsWith("S-1-5-"));
+}
+for (const { mitigations } of getAppContainerTokensWithInfo("Hello", { mitigations: true })!) {
+  Object.values(mitigations!).forEach((enabled) => assert.strictEqual(typeof enabled, "boolean"));
+}
+for (const info of getAppContainerTokensWithInfo("Hello", { microsoftSignedOnly: true })!) {
+  assert(info.microsoftSignedOnly === undefined || typeof info.microsoftSignedOnly === "boolean");
+}
+for (const p of getSandboxedProcesses("Hello", { includeLowIntegrity: true })!) {
+  assert(p.kind === "app_container" ? p.token.endsWith("Hello") : p.integrityLevel <= 0x1000);
+}
+// This test runs at Medium integrity or above, so it's only listed with a
+// threshold at least that high.
+const isListed = (maxIntegrityLevel: number) =>
+  getSandboxedProcesses("Hello", { includeLowIntegrity: true, maxIntegrityLevel })!.some(
+    (p) => p.pid === process.pid
+  );
+if (!isCurrentProcessAppContainer()) {
+  assert(!isListed(0x1000));
+  assert(isListed(0x4000));
+}
+for (const pid of Object.values(getAppContainerTokenMap("Hello")!)) {
+  assert(typeof pid === "number");
+}
+assert.deepStrictEqual(
+  findAppContainerByPackageFamily("Not.A.Real.Package_0000000000000", "Hello"),
+  []
+);
+assert.strictEqual(isCurrentProcessAppContainer(), false);
+assert.strictEqual(getCurrentProcessAppContainerSid(), null);
+assert.deepStrictEqual(whichAreAppContainers([process.pid]), []);
+assert.throws(
+  () => getAppContainerTokenForSid(0, "not a sid", "Hello"),
+  (err: any) => typeof err.win32Error === "number"
+);
+assert(typeof getCurrentSessionId() === "number");
+// TokenSessionId (12) is a single DWORD.
+assert.strictEqual(
+  queryProcessTokenInfo(process.pid, 12, 64)!.readUInt32LE(0),
+  getCurrentSessionId()
+);
+// TokenLinkedToken (19) would hand back a handle nobody closes.
+assert.throws(() => queryProcessTokenInfo(process.pid, 19, 64), RangeError);
+assert.match(getProcessLogonId(process.pid)!, /^0x[0-9a-f]+$/);
+// Process32First fails outright if the entry's dwSize is wrong.
+assert(getProcessCount()! > 0);
+assert.strictEqual(getOpenSnapshotCount(), 0);
+
+// Most processes can't be opened when not elevated; make sure those paths
+// don't leak anything over repeated walks.
+const handlesBefore = getOpenHandleCount()!;
+for (let i = 0; i < 50; i++) {
+  getAppContainerProcessTokens("Hello");
+}
+assert.strictEqual(getOpenSnapshotCount(), 0);
+assert(getOpenHandleCount()! - handlesBefore < 10);
+
+assert.strictEqual(getProcessExitCode(process.pid), null);
+assert.strictEqual(isProcessFrozen(process.pid), false);
+
+const child = spawn(process.execPath, ["-e", "setInterval(() => {}, 1e3)"]);
+const childCreationTime = getProcessCreationTime(child.pid!)!;
+assert(getProcessesCreatedSince(childCreationTime)!.includes(child.pid!));
+assert(!getProcessesCreatedSince(childCreationTime + 1n)!.includes(child.pid!));
+assert.strictEqual(terminateProcess(child.pid!, 1, childCreationTime - 1n), false);
+assert.strictEqual(terminateProcess(child.pid!, 1, childCreationTime), true);
+waitForProcessExit(child.pid!, 5000)?.then((exited) => assert.strictEqual(exited, true));
+
+for (const value of Object.values(probeEnumerationCapabilities()!)) {
+  assert.strictEqual(typeof value, "boolean");
+}
+assert.strictEqual(getOpenSnapshotCount(), 0);
+
+waitForProcessExit(process.pid, 0)?.then((exited) => assert.strictEqual(exited, false));
+assert.rejects(waitForProcessExit(process.pid, Infinity)!, RangeError);
+waitForAppContainerProcess("S-1-15-2-1-2-3-4-5-6-7", 0, "Hello")?.then((token) =>
+  assert.strictEqual(token, null)
+);
+assert.rejects(
+  waitForAppContainerProcess("not a sid", 0, "Hello")!,
+  (err: any) => typeof err.win32Error === "number"
+);
+assert.rejects(waitForAppContainerProcess("S-1-15-2-1", 2 ** 32 - 1, "Hello")!, RangeError);
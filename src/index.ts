@@ -1,9 +1,232 @@
 import { join } from "path";
 
+interface NativeAppContainerObjectPath {
+  pid: number;
+  sessionId: number;
+  objectPath: string;
+}
+
+interface NativeAppContainerProcess extends NativeAppContainerObjectPath {
+  /** Only set when requested, see {@link AppContainerInfoOptions}. */
+  capabilities?: string[];
+  packageFamilyName?: string;
+  aumid?: string;
+  isBackgroundTask?: boolean;
+  moniker?: string;
+  loopbackExempt?: boolean;
+  packageMainPid?: number;
+  ownerSid?: string;
+  mitigations?: ProcessMitigations;
+  microsoftSignedOnly?: boolean;
+}
+
+/**
+ * Options for the native enumeration: the details of
+ * {@link AppContainerInfoOptions}, plus ones only the wrappers here use.
+ */
+interface NativeProcessOptions extends AppContainerInfoOptions {
+  includeLowIntegrity?: boolean;
+  maxIntegrityLevel?: number;
+  inaccessible?: boolean;
+  isProtected?: boolean;
+}
+
+export interface SkippedProcess {
+  pid: number;
+  /** Why the process was skipped. This may be localized. */
+  reason: string;
+  /** The Win32 error code behind `reason`. */
+  win32Error: number;
+}
+
+export interface InaccessibleProcess {
+  pid: number;
+  /**
+   * `access_denied` is expected for protected processes and other users'
+   * processes, `not_found` means the process exited while being looked at.
+   */
+  kind: "access_denied" | "not_found" | "other";
+  /** The Win32 error code of the failed `OpenProcess` or `OpenProcessToken`. */
+  win32Error: number;
+  /**
+   * Whether the process is protected (PP or PPL), like antimalware services,
+   * and so can't be looked at even with more privileges. Only set for
+   * `access_denied` processes, when requested.
+   */
+  isProtected?: boolean;
+}
+
+export interface DetailedTokens {
+  tokens: string[];
+  /** App container processes whose pipe path couldn't be resolved. */
+  skipped: SkippedProcess[];
+  /**
+   * Processes whose token couldn't be opened, so whether they're in an app
+   * container is unknown.
+   */
+  inaccessible: InaccessibleProcess[];
+  /**
+   * Whether the walk was cut short by the process list changing underneath it
+   * and had to be retried on a fresh snapshot. If that kept happening, some
+   * processes may be missing.
+   */
+  retried: boolean;
+}
+
+export interface AppContainerTokenWithCapabilities {
+  token: string;
+  /** Capability SIDs granted to the container, e.g. `S-1-15-3-1`. */
+  capabilities: string[];
+}
+
+/**
+ * Details to include with {@link getAppContainerTokensWithInfo}. Each costs
+ * extra work per process, so request only the ones you need.
+ */
+export interface AppContainerInfoOptions {
+  /** Include the capability SIDs granted to each container. */
+  capabilities?: boolean;
+  /** Include the package family name of packaged processes. */
+  packageFamilyName?: boolean;
+  /** Include the Application User Model ID of packaged app processes. */
+  aumid?: boolean;
+  /** Include whether each process is a packaged app's background task host. */
+  isBackgroundTask?: boolean;
+  /** Include the moniker each container is registered under. */
+  moniker?: boolean;
+  /** Include whether each container is exempt from loopback isolation. */
+  loopbackExempt?: boolean;
+  /** Include the ID of the main process of each container's app. */
+  packageMainPid?: boolean;
+  /** Include the SID of the user each process runs as. */
+  ownerSid?: boolean;
+  /** Include the exploit mitigations enabled for each process. */
+  mitigations?: boolean;
+  /** Include whether each process may only load Microsoft-signed images. */
+  microsoftSignedOnly?: boolean;
+}
+
+/**
+ * Exploit mitigations enabled for a process. Each is unset if its policy
+ * couldn't be read.
+ */
+export interface ProcessMitigations {
+  /** Data Execution Prevention. */
+  dep?: boolean;
+  /** Bottom-up ASLR, randomizing allocation addresses. */
+  bottomUpAslr?: boolean;
+  /** Mandatory ASLR, relocating images not built for it. */
+  forceRelocateImages?: boolean;
+  /** High-entropy (64-bit) ASLR. */
+  highEntropyAslr?: boolean;
+  /** Control Flow Guard. */
+  cfg?: boolean;
+  /** Arbitrary Code Guard, preventing the process from creating executable code. */
+  dynamicCodeProhibited?: boolean;
+}
+
+export interface AppContainerTokenInfo {
+  token: string;
+  pid: number;
+  /** Capability SIDs granted to the container, e.g. `S-1-15-3-1`. */
+  capabilities?: string[];
+  /** Package family name, unset if the process isn't packaged. */
+  packageFamilyName?: string;
+  /**
+   * Application User Model ID, e.g. `Microsoft.WindowsCalculator_8wekyb3d8bbwe!App`,
+   * tying the process to its Start menu entry. Unset if the process has none.
+   */
+  aumid?: string;
+  /**
+   * Whether the process hosts a packaged app's background task, judged only by
+   * its image being `backgroundTaskHost.exe` (compared case-insensitively).
+   * Best-effort: tasks that run in the app's own process aren't detected.
+   */
+  isBackgroundTask?: boolean;
+  /**
+   * The container's short name from the current user's AppContainer mappings
+   * in the registry, e.g. a lowercase package family name. Unset if the
+   * container isn't registered for the current user.
+   */
+  moniker?: string;
+  /**
+   * Whether the container may connect to localhost, as allowed with
+   * `CheckNetIsolation LoopbackExempt -a`. False if the exemptions couldn't be
+   * read, in which case a warning is emitted.
+   */
+  loopbackExempt?: boolean;
+  /**
+   * ID of the earliest-created process in the same app container, for grouping
+   * e.g. background tasks under their app. This may be the process itself.
+   * Only processes returned alongside it are considered.
+   */
+  packageMainPid?: number;
+  /**
+   * SID of the user the process runs as, e.g. `S-1-5-21-...`, for grouping
+   * processes by user. It isn't resolved to an account name.
+   */
+  ownerSid?: string;
+  /** Exploit mitigations enabled for the process. */
+  mitigations?: ProcessMitigations;
+  /**
+   * Whether the process' signature policy only allows loading images signed
+   * by Microsoft. Unset if the policy couldn't be read.
+   */
+  microsoftSignedOnly?: boolean;
+}
+
+export type SandboxedProcess =
+  | { kind: "app_container"; pid: number; token: string }
+  | { kind: "low_integrity"; pid: number; integrityLevel: number };
+
+export interface SessionTokens {
+  sessionId: number;
+  tokens: string[];
+}
+
+export interface EnumerationCapabilities {
+  /** Whether this process runs elevated. */
+  isElevated: boolean;
+  /** Whether SeDebugPrivilege is enabled, not merely held, on this process. */
+  hasDebugPrivilege: boolean;
+  /** Whether any of a few processes in other sessions could be opened. */
+  canOpenOtherSession: boolean;
+  /** Whether the token of one of those could be opened for querying. */
+  sampleTokenReadable: boolean;
+}
+
 let native:
   | undefined
   | {
-      getAppContainerProcessTokens(): string[];
+      getAppContainerProcesses(options?: NativeProcessOptions): {
+        processes: NativeAppContainerProcess[];
+        skipped: SkippedProcess[];
+        lowIntegrity: { pid: number; integrityLevel: number }[];
+        inaccessible: InaccessibleProcess[];
+        retried: boolean;
+      };
+      isCurrentProcessAppContainer(): boolean;
+      getCurrentProcessAppContainerSid(): string | null;
+      whichAreAppContainers(pids: number[]): number[];
+      getNamedObjectPathForSid(sid: string): string | null;
+      decodeObjectPath(buffer: Uint16Array, returnLength: number): string;
+      getProcessLogonId(pid: number): string;
+      queryProcessTokenInfo(pid: number, infoClass: number, maxBytes: number): Buffer;
+      getCurrentSessionId(): number;
+      getOpenSnapshotCount(): number;
+      getOpenHandleCount(): number;
+      getProcessCount(): number;
+      getProcessesCreatedSince(since: bigint): number[];
+      waitForProcessExit(pid: number, timeoutMs: number): Promise<boolean>;
+      waitForAppContainerProcess(
+        sid: string,
+        timeoutMs: number
+      ): Promise<NativeAppContainerObjectPath | null>;
+      getProcessExitCode(pid: number): number | null;
+      getProcessCreationTime(pid: number): bigint;
+      terminateProcess(pid: number, exitCode: number, expectedCreationTime: bigint): boolean;
+      isProcessFrozen(pid: number): boolean;
+      probeEnumerationCapabilities(): EnumerationCapabilities;
     };
 
 const getModule = () => {
@@ -15,7 +238,308 @@ const getModule = () => {
   return native;
 };
 
+const sessionScopedObjectPath = /^\\?(Sessions\\\d+\\.*)$/i;
+
+/**
+ * Formats the named pipe path for an app container's named object path in the
+ * given session. Object paths that are already session-scoped are used as-is,
+ * rather than being prefixed a second time. For paths read from a token, that
+ * session is always the token's own session. Throws a `RangeError` for an
+ * empty object path, which can't name a container.
+ */
+export const formatPipePath = (sessionId: number, objectPath: string) => {
+  if (!objectPath) {
+    throw new RangeError("objectPath must not be empty");
+  }
+
+  const scoped = sessionScopedObjectPath.exec(objectPath);
+  return scoped
+    ? `\\\\.\\pipe\\${scoped[1]}`
+    : `\\\\.\\pipe\\Sessions\\${sessionId}\\${objectPath}`;
+};
+
+const toToken = (process: NativeAppContainerObjectPath, suffix: string) =>
+  join(formatPipePath(process.sessionId, process.objectPath), suffix);
+
 export const getAppContainerProcessTokens = (suffix: string) =>
   getModule()
-    ?.getAppContainerProcessTokens()
-    .map((path) => join(path, suffix));
+    ?.getAppContainerProcesses()
+    .processes.map((p) => toToken(p, suffix));
+
+/**
+ * Like {@link getAppContainerProcessTokens}, but also reports app container
+ * processes that were found but whose pipe path couldn't be resolved, and
+ * processes that couldn't be looked at. With `isProtected`, each of those that
+ * denied access is also checked for being a protected process, at the cost of
+ * opening it again.
+ */
+export const getAppContainerTokensDetailed = (
+  suffix: string,
+  { isProtected = false } = {}
+): DetailedTokens | undefined => {
+  const result = getModule()?.getAppContainerProcesses({ inaccessible: true, isProtected });
+  return (
+    result && {
+      tokens: result.processes.map((p) => toToken(p, suffix)),
+      skipped: result.skipped,
+      inaccessible: result.inaccessible,
+      retried: result.retried,
+    }
+  );
+};
+
+/**
+ * Like {@link getAppContainerProcessTokens}, but grouped by the session each
+ * app container process is running in.
+ */
+export const getAppContainerTokensBySession = (suffix: string) => {
+  const processes = getModule()?.getAppContainerProcesses().processes;
+  if (!processes) {
+    return;
+  }
+
+  const bySession = new Map<number, SessionTokens>();
+  for (const p of processes) {
+    let group = bySession.get(p.sessionId);
+    if (!group) {
+      group = { sessionId: p.sessionId, tokens: [] };
+      bySession.set(p.sessionId, group);
+    }
+
+    group.tokens.push(toToken(p, suffix));
+  }
+
+  return [...bySession.values()];
+};
+
+/**
+ * Gets whether this process is itself running inside an app container.
+ */
+export const isCurrentProcessAppContainer = () => getModule()?.isCurrentProcessAppContainer();
+
+/**
+ * Gets the AppContainer SID of this process, or null if it isn't running in
+ * an app container.
+ */
+export const getCurrentProcessAppContainerSid = () =>
+  getModule()?.getCurrentProcessAppContainerSid();
+
+/**
+ * Gets which of the given processes are running in an app container. This
+ * opens only those processes, so it's cheaper than a full enumeration when the
+ * candidates are already known.
+ */
+export const whichAreAppContainers = (pids: number[]) =>
+  getModule()?.whichAreAppContainers(pids);
+
+/**
+ * Gets the app container token for the container with the given SID
+ * (`S-1-15-2-...`) in the given session, without needing a process from it.
+ * Returns null if the container's object path can't be resolved.
+ */
+export const getAppContainerTokenForSid = (sessionId: number, sid: string, suffix: string) => {
+  const objectPath = getModule()?.getNamedObjectPathForSid(sid);
+  return objectPath && join(formatPipePath(sessionId, objectPath), suffix);
+};
+
+/**
+ * Decodes an object path buffer the way the enumeration does: up to the first
+ * null, or up to `returnLength` characters if there's none.
+ * @internal Only exported for the tests.
+ */
+export const decodeObjectPath = (buffer: Uint16Array, returnLength: number) =>
+  getModule()?.decodeObjectPath(buffer, returnLength);
+
+/**
+ * Gets the logon session (authentication LUID) of the process as a hex
+ * string, e.g. `0x3e7` for SYSTEM. Unlike the Terminal Services session ID,
+ * this distinguishes separate logons such as network vs. interactive ones.
+ */
+export const getProcessLogonId = (pid: number) => getModule()?.getProcessLogonId(pid);
+
+/**
+ * Escape hatch that returns the raw bytes of `GetTokenInformation` for the
+ * process' token and the given `TOKEN_INFORMATION_CLASS` value. Throws if the
+ * data doesn't fit in `maxBytes` (at most 1MB). Prefer the friendlier
+ * functions in this module where they exist.
+ *
+ * The bytes are a copy, so pointer fields inside them, such as the SIDs of
+ * `TOKEN_USER` or `TOKEN_GROUPS`, point into a native buffer that has already
+ * been freed and must not be followed. Classes that return a handle
+ * (`TokenLinkedToken`) throw a `RangeError`.
+ */
+export const queryProcessTokenInfo = (pid: number, infoClass: number, maxBytes: number) =>
+  getModule()?.queryProcessTokenInfo(pid, infoClass, maxBytes);
+
+/**
+ * Gets the ID of the session this process is running in, for use with
+ * {@link getAppContainerTokensBySession}. Note this is the caller's own
+ * session, which isn't necessarily the active console session, e.g. when
+ * connected over Remote Desktop.
+ */
+export const getCurrentSessionId = () => getModule()?.getCurrentSessionId();
+
+/**
+ * Gets the number of process snapshots the addon currently has open. This
+ * should be zero whenever no call is in progress; anything else is a leak.
+ */
+export const getOpenSnapshotCount = () => getModule()?.getOpenSnapshotCount();
+
+/**
+ * Gets the number of handles this process has open, for detecting leaks.
+ * @internal Only exported for the tests.
+ */
+export const getOpenHandleCount = () => getModule()?.getOpenHandleCount();
+
+/**
+ * Gets the number of processes running on the system. This doesn't open any
+ * of them, so it's cheap.
+ */
+export const getProcessCount = () => getModule()?.getProcessCount();
+
+/**
+ * Gets the IDs of processes created at or after `since`, a raw FILETIME like
+ * the ones from {@link getProcessCreationTime}. Processes whose creation time
+ * can't be read are left out.
+ */
+export const getProcessesCreatedSince = (since: bigint) =>
+  getModule()?.getProcessesCreatedSince(since);
+
+/**
+ * Resolves to true once the process exits (or if it doesn't exist), or to
+ * false if it's still running after `timeoutMs`. The wait occupies a libuv
+ * threadpool thread, so `timeoutMs` is at most an hour (3600000); longer
+ * timeouts, including `Infinity`, are rejected, as are other failures.
+ */
+export const waitForProcessExit = (pid: number, timeoutMs: number) =>
+  getModule()?.waitForProcessExit(pid, timeoutMs);
+
+/**
+ * Waits for a process to start in the app container with the given SID, and
+ * resolves to its token. Resolves to null if none appears within `timeoutMs`.
+ * Like {@link waitForProcessExit}, `timeoutMs` is at most an hour (3600000),
+ * and invalid arguments, including a malformed SID, reject the promise.
+ */
+export const waitForAppContainerProcess = (sid: string, timeoutMs: number, suffix: string) =>
+  getModule()
+    ?.waitForAppContainerProcess(sid, timeoutMs)
+    .then((p) => p && toToken(p, suffix));
+
+/**
+ * Gets the exit code of a process that has exited but is still referenced by
+ * an open handle, or null if the process is still running.
+ */
+export const getProcessExitCode = (pid: number) => getModule()?.getProcessExitCode(pid);
+
+/**
+ * Gets a map of app container tokens to the ID of the process that owns
+ * them. When several processes share a container, the first one found wins.
+ */
+export const getAppContainerTokenMap = (suffix: string) => {
+  const processes = getModule()?.getAppContainerProcesses().processes;
+  if (!processes) {
+    return;
+  }
+
+  const map: Record<string, number> = {};
+  for (const p of processes) {
+    map[toToken(p, suffix)] ??= p.pid;
+  }
+
+  return map;
+};
+
+/**
+ * Gets the app container tokens of running processes belonging to the given
+ * package family, e.g. `Microsoft.WindowsCalculator_8wekyb3d8bbwe`.
+ */
+export const findAppContainerByPackageFamily = (family: string, suffix: string) =>
+  getModule()
+    ?.getAppContainerProcesses({ packageFamilyName: true })
+    .processes.filter((p) => p.packageFamilyName?.toLowerCase() === family.toLowerCase())
+    .map((p) => toToken(p, suffix));
+
+/**
+ * Gets the process' creation time as a raw FILETIME (100ns intervals since
+ * 1601-01-01 UTC). Together with the PID, this identifies a process even if
+ * the PID is later reused.
+ */
+export const getProcessCreationTime = (pid: number) => getModule()?.getProcessCreationTime(pid);
+
+/**
+ * Terminates the process, but only if its creation time still matches the one
+ * from {@link getProcessCreationTime}, so a reused PID can't cause another
+ * process to be killed. Returns false if the process is gone or was replaced.
+ * Throws if it can't be terminated, e.g. with `win32Error` 5 when access is denied.
+ */
+export const terminateProcess = (pid: number, exitCode: number, expectedCreationTime: bigint) =>
+  getModule()?.terminateProcess(pid, exitCode, expectedCreationTime);
+
+/**
+ * Gets whether all of the process' threads are suspended, as happens when
+ * the system freezes a backgrounded UWP app. Returns false if this can't be
+ * determined.
+ */
+export const isProcessFrozen = (pid: number) => getModule()?.isProcessFrozen(pid);
+
+/**
+ * Cheaply checks how much of the system an enumeration from this process can
+ * see, so callers can explain why results might be incomplete before running
+ * one. Processes whose tokens can't be opened are left out of the results.
+ */
+export const probeEnumerationCapabilities = () => getModule()?.probeEnumerationCapabilities();
+
+/**
+ * Like {@link getAppContainerProcessTokens}, but returns each token along with
+ * the ID of its process and the details requested in `options`.
+ */
+export const getAppContainerTokensWithInfo = (
+  suffix: string,
+  options: AppContainerInfoOptions
+): AppContainerTokenInfo[] | undefined =>
+  getModule()
+    ?.getAppContainerProcesses(options)
+    .processes.map(({ sessionId, objectPath, ...info }) => ({
+      token: toToken({ pid: info.pid, sessionId, objectPath }, suffix),
+      ...info,
+    }));
+
+/**
+ * Like {@link getAppContainerProcessTokens}, but also returns the capabilities
+ * granted to each app container process. Shorthand for
+ * {@link getAppContainerTokensWithInfo} with only `capabilities` requested.
+ */
+export const getAppContainerTokensWithCapabilities = (
+  suffix: string
+): AppContainerTokenWithCapabilities[] | undefined =>
+  getAppContainerTokensWithInfo(suffix, { capabilities: true })?.map(({ token, capabilities }) => ({
+    token,
+    capabilities: capabilities!,
+  }));
+
+/**
+ * Gets sandboxed processes: app container processes along with their tokens
+ * and, with `includeLowIntegrity`, processes outside of an app container that
+ * run at `maxIntegrityLevel` or below. That's an integrity level RID and
+ * defaults to Low (`0x1000`); pass e.g. `0x2000` to include Medium. Those
+ * processes have no app container pipe, so only their RID is returned.
+ */
+export const getSandboxedProcesses = (
+  suffix: string,
+  { includeLowIntegrity = false, maxIntegrityLevel = 0x1000 } = {}
+): SandboxedProcess[] | undefined => {
+  const result = getModule()?.getAppContainerProcesses({
+    includeLowIntegrity,
+    maxIntegrityLevel,
+  });
+  return (
+    result && [
+      ...result.processes.map((p) => ({
+        kind: "app_container" as const,
+        pid: p.pid,
+        token: toToken(p, suffix),
+      })),
+      ...result.lowIntegrity.map((p) => ({ kind: "low_integrity" as const, ...p })),
+    ]
+  );
+};
@@ -1,14 +1,32 @@
 #include <windows.h>
 #include <TlHelp32.h>
+#include <appmodel.h>
+#include <netfw.h>
+#include <winternl.h>
+#include <sddl.h>
+#include <algorithm>
+#include <atomic>
+#include <cstdio>
 #include <string>
-#include <sstream>
+#include <unordered_map>
+#include <unordered_set>
+#include <vector>
 #include <napi.h>
 
+#ifndef STATUS_INFO_LENGTH_MISMATCH
+#define STATUS_INFO_LENGTH_MISMATCH ((NTSTATUS)0xC0000004L)
+#endif
+
+// KTHREAD_STATE::Waiting and KWAIT_REASON::Suspended, which aren't in the
+// public headers.
+const ULONG ThreadStateWaiting = 5;
+const ULONG WaitReasonSuspended = 5;
+
 /**
- * Schedules a JS error to be thrown via NAPI. Note that this doesn't actually
- * throw a C++ exception. Code should usually return after calling this.
+ * Formats the given message followed by the system message for the last
+ * Win32 error.
  */
-void ThrowJsError(Napi::Env env, const char *msg) {
+std::string FormatLastError(const char *msg) {
 
   auto errMsg = std::string(msg);
 
@@ -22,99 +40,1712 @@ void ThrowJsError(Napi::Env env, const char *msg) {
   errMsg += ": ";
   errMsg += sysMsg;
 
-  Napi::Error::New(env, errMsg).ThrowAsJavaScriptException();
+  return errMsg;
 }
 
-BOOL addAppContainerProcessName(Napi::Env env, Napi::Array tokens,
-                                HANDLE hToken) {
-  ULONG ulSessionId;
+/**
+ * Creates a JS error for the last Win32 error. The Win32 error code is
+ * attached as `win32Error`, so callers can act on (or localize) specific
+ * failures without parsing the message. This isn't `errno`, which Node uses
+ * for negative libuv error codes.
+ */
+Napi::Error CreateJsError(Napi::Env env, const char *msg) {
+  auto dwError = GetLastError();
+  auto err = Napi::Error::New(env, FormatLastError(msg));
+  err.Set("win32Error", Napi::Number::New(env, dwError));
+  return err;
+}
+
+/**
+ * Schedules a JS error to be thrown via NAPI. Note that this doesn't actually
+ * throw a C++ exception. Code should usually return after calling this.
+ */
+void ThrowJsError(Napi::Env env, const char *msg) {
+  CreateJsError(env, msg).ThrowAsJavaScriptException();
+}
+
+/**
+ * Why a process was skipped: a message for humans, and the Win32 error code
+ * for code, since the message comes from the system and may be localized.
+ * `warn` marks conditions that shouldn't happen, which callers on the main
+ * thread also report as a warning.
+ */
+struct SkipReason {
+  std::string message;
+  DWORD dwError;
+  bool warn = false;
+};
+
+/**
+ * Creates a skip reason for the last Win32 error.
+ */
+SkipReason LastErrorReason(const char *msg) {
+  auto dwError = GetLastError();
+  return {FormatLastError(msg), dwError};
+}
+
+/**
+ * Emits a warning via Node's `process.emitWarning`. Used for conditions that
+ * shouldn't fail the whole call but that we'd still like to hear about.
+ */
+void EmitJsWarning(Napi::Env env, const std::string &msg) {
+  auto process = env.Global().Get("process").As<Napi::Object>();
+  auto emitWarning = process.Get("emitWarning").As<Napi::Function>();
+  emitWarning.Call(process, {Napi::String::New(env, msg)});
+}
+
+/**
+ * Maximum number of attempts at creating or walking a snapshot when that's
+ * cut short by a transient error.
+ */
+const int MaxSnapshotAttempts = 3;
+
+/**
+ * Returns whether the last `Process32Next` call failed because the process
+ * list changed underneath it, rather than because it reached the end.
+ */
+bool IsSnapshotWalkTruncated() { return GetLastError() != ERROR_NO_MORE_FILES; }
+
+/**
+ * Upper bound for raw token information queries from JS.
+ */
+const DWORD MaxTokenInfoBytes = 1024 * 1024;
+
+/**
+ * Access used to open processes in the token walks. The probe uses the same
+ * access, so that it reports what the walks will actually see.
+ */
+const DWORD WalkProcessAccess = PROCESS_QUERY_INFORMATION;
+
+/**
+ * Closes a handle when it goes out of scope, so that no early return in
+ * per-process code can leak it.
+ */
+class ScopedHandle {
+public:
+  explicit ScopedHandle(HANDLE h = NULL) : h(h) {}
+  ~ScopedHandle() {
+    // Callers often read the last error after an early return, so don't let
+    // closing the handle clobber it.
+    if (h != NULL && h != INVALID_HANDLE_VALUE) {
+      auto dwError = GetLastError();
+      CloseHandle(h);
+      SetLastError(dwError);
+    }
+  }
+
+  ScopedHandle(const ScopedHandle &) = delete;
+  ScopedHandle &operator=(const ScopedHandle &) = delete;
+
+  HANDLE get() const { return h; }
+  HANDLE *put() { return &h; }
+
+private:
+  HANDLE h;
+};
+
+/**
+ * Returns whether the token belongs to an app container. Returns false if
+ * that couldn't be determined.
+ */
+bool IsAppContainerToken(Napi::Env env, HANDLE hToken) {
+  ULONG ulIsAppContainer;
+  DWORD dwReturnLength;
+
+  if (!GetTokenInformation(hToken, TokenIsAppContainer, &ulIsAppContainer,
+                           sizeof(ulIsAppContainer), &dwReturnLength)) {
+    return false;
+  }
+
+  if (dwReturnLength != sizeof(ulIsAppContainer)) {
+    EmitJsWarning(env, "Unexpected TokenIsAppContainer length " +
+                           std::to_string(dwReturnLength));
+    return false;
+  }
+
+  return ulIsAppContainer != 0;
+}
+
+/**
+ * Reads a variable-length token information class into `buffer`.
+ */
+bool GetTokenInformationBuffer(HANDLE hToken, TOKEN_INFORMATION_CLASS infoClass,
+                               std::vector<BYTE> &buffer) {
+  DWORD dwLength = 0;
+  GetTokenInformation(hToken, infoClass, NULL, 0, &dwLength);
+  if (dwLength == 0) {
+    return false;
+  }
+
+  buffer.resize(dwLength);
+  return GetTokenInformation(hToken, infoClass, buffer.data(), dwLength,
+                             &dwLength);
+}
+
+/**
+ * Converts a SID to its `S-1-...` string form.
+ */
+bool SidToString(PSID pSid, std::u16string &sid) {
+  LPWSTR sidString;
+  if (pSid == NULL || !ConvertSidToStringSidW(pSid, &sidString)) {
+    return false;
+  }
+
+  sid = std::u16string(sidString, sidString + wcslen(sidString));
+  LocalFree(sidString);
+  return true;
+}
+
+/**
+ * Reads the token's AppContainer SID as a string. Returns false if the token
+ * has none or it couldn't be read.
+ */
+bool GetAppContainerSid(HANDLE hToken, std::u16string &sid) {
+  std::vector<BYTE> buffer;
+  if (!GetTokenInformationBuffer(hToken, TokenAppContainerSid, buffer)) {
+    return false;
+  }
+
+  auto pInfo =
+      reinterpret_cast<TOKEN_APPCONTAINER_INFORMATION *>(buffer.data());
+  return SidToString(pInfo->TokenAppContainer, sid);
+}
+
+/**
+ * Opens the token of the process with the given ID for querying. Returns the
+ * name of the call that failed, with its error left in `GetLastError`, or
+ * NULL on success.
+ */
+const char *OpenProcessTokenById(DWORD pid, ScopedHandle &hToken) {
+  ScopedHandle hProcess(
+      OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid));
+  if (hProcess.get() == NULL) {
+    return "OpenProcess";
+  }
+
+  if (!OpenProcessToken(hProcess.get(), TOKEN_QUERY, hToken.put())) {
+    return "OpenProcessToken";
+  }
+
+  return NULL;
+}
+
+/**
+ * Reads the token's integrity level RID, e.g. SECURITY_MANDATORY_LOW_RID.
+ */
+bool GetIntegrityLevel(HANDLE hToken, DWORD &dwIntegrityLevel) {
+  std::vector<BYTE> buffer;
+  if (!GetTokenInformationBuffer(hToken, TokenIntegrityLevel, buffer)) {
+    return false;
+  }
+
+  auto pLabel = reinterpret_cast<TOKEN_MANDATORY_LABEL *>(buffer.data());
+  auto subAuthorityCount = *GetSidSubAuthorityCount(pLabel->Label.Sid);
+  if (subAuthorityCount == 0) {
+    return false;
+  }
+
+  dwIntegrityLevel =
+      *GetSidSubAuthority(pLabel->Label.Sid, subAuthorityCount - 1);
+  return true;
+}
+
+/**
+ * Reads the capability SIDs granted to the token.
+ */
+Napi::Array GetTokenCapabilities(Napi::Env env, HANDLE hToken) {
+  auto capabilities = Napi::Array::New(env);
+
+  std::vector<BYTE> buffer;
+  if (!GetTokenInformationBuffer(hToken, TokenCapabilities, buffer)) {
+    return capabilities;
+  }
+
+  auto pGroups = reinterpret_cast<TOKEN_GROUPS *>(buffer.data());
+  for (DWORD i = 0; i < pGroups->GroupCount; i++) {
+    std::u16string sid;
+    if (SidToString(pGroups->Groups[i].Sid, sid)) {
+      capabilities[capabilities.Length()] = Napi::String::New(env, sid.c_str());
+    }
+  }
+
+  return capabilities;
+}
+
+/**
+ * Number of toolhelp snapshots currently open, so tests can check that none
+ * are leaked.
+ */
+std::atomic<uint32_t> openSnapshotCount(0);
+
+/**
+ * Takes a process snapshot. Creating one can fail transiently while processes
+ * are starting and exiting, so those errors are retried with a short backoff.
+ */
+HANDLE OpenProcessSnapshot() {
+  for (int attempt = 1;; attempt++) {
+    auto hSnapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
+    if (hSnapshot != INVALID_HANDLE_VALUE) {
+      openSnapshotCount++;
+      return hSnapshot;
+    }
+
+    auto dwError = GetLastError();
+    if ((dwError != ERROR_PARTIAL_COPY && dwError != ERROR_BAD_LENGTH) ||
+        attempt == MaxSnapshotAttempts) {
+      return hSnapshot;
+    }
+
+    Sleep(10 * attempt);
+  }
+}
+
+void CloseProcessSnapshot(HANDLE hSnapshot) {
+  CloseHandle(hSnapshot);
+  openSnapshotCount--;
+}
+
+/**
+ * Walks a snapshot of all processes, calling `callback` with each entry until
+ * it returns false. If the walk is cut short by a transient error, it's
+ * retried on a fresh snapshot, skipping processes that were already visited.
+ *
+ * This doesn't touch JS, so it's safe to use from worker threads. Returns the
+ * name of the call that failed, with its error left in `GetLastError`, or
+ * NULL on success. If given, `pRetried` is set to whether the walk had to be
+ * retried.
+ */
+template <typename F>
+const char *ForEachProcess(F callback, bool *pRetried = NULL) {
+  std::unordered_set<DWORD> seenPids;
+  if (pRetried) {
+    *pRetried = false;
+  }
+
+  for (int attempt = 1;; attempt++) {
+    // Take a snapshot of all processes in the system.
+    auto hProcessSnap = OpenProcessSnapshot();
+    if (hProcessSnap == INVALID_HANDLE_VALUE) {
+      return "CreateToolhelp32Snapshot";
+    }
+
+    // Set the size of the structure before using it. This is taken from the
+    // variable itself so it can't drift if the entry type changes, e.g. to
+    // PROCESSENTRY32W, which would make Process32First fail.
+    PROCESSENTRY32 pe32;
+    pe32.dwSize = sizeof(pe32);
+
+    // Retrieve information about the first process,
+    // and exit if unsuccessful
+    if (!Process32First(hProcessSnap, &pe32)) {
+      auto dwError = GetLastError();
+      CloseProcessSnapshot(hProcessSnap);
+      SetLastError(dwError);
+      return "Process32First";
+    }
+
+    auto stopped = false;
+    do {
+      if (seenPids.insert(pe32.th32ProcessID).second) {
+        stopped = !callback(pe32);
+      }
+    } while (!stopped && Process32Next(hProcessSnap, &pe32));
+
+    auto truncated = !stopped && IsSnapshotWalkTruncated();
+    CloseProcessSnapshot(hProcessSnap);
+
+    if (!truncated || attempt == MaxSnapshotAttempts) {
+      return NULL;
+    }
+
+    if (pRetried) {
+      *pRetried = true;
+    }
+  }
+}
+
+/**
+ * Decodes an object path written by GetAppContainerNamedObjectPath. It's
+ * normally null-terminated, but if the API filled the whole buffer without a
+ * null, the returned length is used to bound it instead.
+ */
+std::u16string DecodeObjectPath(const WCHAR *buffer, ULONG ulBufferLength,
+                                ULONG ulReturnLength) {
+  auto end = buffer + std::min(ulReturnLength, ulBufferLength);
+  return std::u16string(buffer, std::find(buffer, end, L'\0'));
+}
+
+/**
+ * Reads the token's session ID and app container named object path. On
+ * failure, returns false and sets `reason` to why. This doesn't touch JS, so
+ * it's safe to use from worker threads.
+ */
+bool GetAppContainerObjectPath(HANDLE hToken, ULONG &ulSessionId,
+                               std::u16string &objectPath,
+                               SkipReason &reason) {
   ULONG ulReturnLength;
   WCHAR ObjectPath[1024] = L"";
-  std::wstringstream stringStream;
-  std::wstring strPipeName;
 
   if (!GetTokenInformation(hToken, TokenSessionId, &ulSessionId,
                            sizeof(ulSessionId), &ulReturnLength)) {
+    reason = LastErrorReason("GetTokenInformation(TokenSessionId)");
     return false;
   }
 
-  stringStream.str(L"");
-  stringStream << ulSessionId;
-
-  strPipeName = L"\\\\.\\pipe\\Sessions\\";
-  strPipeName += stringStream.str();
-  strPipeName += L"\\";
+  if (ulReturnLength != sizeof(ulSessionId)) {
+    reason = {"Unexpected TokenSessionId length " +
+                  std::to_string(ulReturnLength),
+              ERROR_BAD_LENGTH, true};
+    return false;
+  }
 
+  // Given a token, the object path is resolved for that token's own app
+  // container and is relative to its session's named object directory. The
+  // session ID read above comes from the same token, so the two always agree,
+  // even for tokens from another session than ours.
   if (!GetAppContainerNamedObjectPath(hToken, NULL,
                                       sizeof(ObjectPath) / sizeof(WCHAR),
                                       ObjectPath, &ulReturnLength)) {
-    return false; // just ignore any errors that happen here
+    reason = LastErrorReason("GetAppContainerNamedObjectPath");
+    return false;
+  }
+
+  objectPath = DecodeObjectPath(ObjectPath, sizeof(ObjectPath) / sizeof(WCHAR),
+                                ulReturnLength);
+
+  // An empty path would otherwise become a pipe path for the session itself.
+  if (objectPath.empty()) {
+    reason = {"GetAppContainerNamedObjectPath returned an empty path",
+              ERROR_INVALID_DATA};
+    return false;
   }
 
-  strPipeName += ObjectPath;
-  auto pipeNameU16 = std::u16string(strPipeName.begin(), strPipeName.end());
-  tokens[tokens.Length()] = Napi::String::New(env, pipeNameU16.c_str());
   return true;
 }
 
-Napi::Value getAppContainerProcessTokens(const Napi::CallbackInfo &info) {
-  Napi::Env env = info.Env();
+/**
+ * Reads the moniker registered for the app container with the given SID, which
+ * for packaged apps is typically their package family name in lowercase. The
+ * mappings are per user, so this only finds containers registered for the
+ * current user.
+ */
+bool GetAppContainerMoniker(const std::u16string &sid,
+                            std::u16string &moniker) {
+  std::wstring subKey =
+      L"Software\\Classes\\Local Settings\\Software\\Microsoft\\Windows"
+      L"\\CurrentVersion\\AppContainer\\Mappings\\";
+  subKey.append(sid.begin(), sid.end());
 
-  // Take a snapshot of all processes in the system.
-  auto hProcessSnap = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
-  if (hProcessSnap == INVALID_HANDLE_VALUE) {
-    ThrowJsError(env, "CreateToolhelp32Snapshot: ");
-    return env.Null();
+  DWORD cbData = 0;
+  if (RegGetValueW(HKEY_CURRENT_USER, subKey.c_str(), L"Moniker",
+                   RRF_RT_REG_SZ, NULL, NULL, &cbData) != ERROR_SUCCESS) {
+    return false;
   }
 
-  // Set the size of the structure before using it.
-  PROCESSENTRY32 pe32;
-  pe32.dwSize = sizeof(PROCESSENTRY32);
-
-  // Retrieve information about the first process,
-  // and exit if unsuccessful
-  if (!Process32First(hProcessSnap, &pe32)) {
-    CloseHandle(hProcessSnap);
-    ThrowJsError(env, "Process32First: ");
-    return env.Null();
+  std::vector<WCHAR> buffer(cbData / sizeof(WCHAR) + 1);
+  cbData = static_cast<DWORD>(buffer.size() * sizeof(WCHAR));
+  if (RegGetValueW(HKEY_CURRENT_USER, subKey.c_str(), L"Moniker",
+                   RRF_RT_REG_SZ, NULL, buffer.data(),
+                   &cbData) != ERROR_SUCCESS) {
+    return false;
   }
 
-  auto tokens = Napi::Array::New(env);
+  moniker =
+      std::u16string(buffer.data(), buffer.data() + wcslen(buffer.data()));
+  return true;
+}
 
-  // Now walk the snapshot of processes, and gather process tokens
-  do {
-    auto hProcess =
-        OpenProcess(PROCESS_QUERY_INFORMATION, FALSE, pe32.th32ProcessID);
-    if (hProcess == NULL) {
-      continue;
+/**
+ * The app containers exempted from loopback network isolation, e.g. with
+ * `CheckNetIsolation LoopbackExempt -a`. Loaded once per enumeration.
+ */
+class LoopbackExemptions {
+public:
+  LoopbackExemptions() = default;
+  LoopbackExemptions(const LoopbackExemptions &) = delete;
+  LoopbackExemptions &operator=(const LoopbackExemptions &) = delete;
+
+  ~LoopbackExemptions() {
+    // The array and each SID in it are allocated on the process heap.
+    if (pSids != NULL) {
+      for (DWORD i = 0; i < dwCount; i++) {
+        HeapFree(GetProcessHeap(), 0, pSids[i].Sid);
+      }
+      HeapFree(GetProcessHeap(), 0, pSids);
     }
+  }
 
-    HANDLE hProcessToken;
-    ULONG ulIsAppContainer;
-    DWORD dwReturnLength;
+  /**
+   * Loads the exemptions, returning the Win32 error code.
+   */
+  DWORD Load() {
+    return NetworkIsolationGetAppContainerConfig(&dwCount, &pSids);
+  }
 
-    if (OpenProcessToken(hProcess, TOKEN_QUERY, &hProcessToken)) {
-      if (GetTokenInformation(hProcessToken, TokenIsAppContainer,
-                              &ulIsAppContainer, sizeof(ulIsAppContainer),
-                              &dwReturnLength)) {
-        if (ulIsAppContainer) {
-          addAppContainerProcessName(env, tokens, hProcessToken);
-        }
-        CloseHandle(hProcessToken);
+  /**
+   * Returns whether the container with the given SID is exempted. This is
+   * false for all of them if loading the exemptions failed.
+   */
+  bool Contains(PSID pSid) const {
+    for (DWORD i = 0; pSid != NULL && pSids != NULL && i < dwCount; i++) {
+      if (EqualSid(pSids[i].Sid, pSid)) {
+        return true;
       }
+    }
+
+    return false;
+  }
+
+private:
+  DWORD dwCount = 0;
+  PSID_AND_ATTRIBUTES pSids = NULL;
+};
+
+/**
+ * Reads the process' creation time as a raw FILETIME value.
+ */
+bool GetProcessCreationTime(HANDLE hProcess, uint64_t &creationTime) {
+  FILETIME ftCreation, ftExit, ftKernel, ftUser;
+  if (!GetProcessTimes(hProcess, &ftCreation, &ftExit, &ftKernel, &ftUser)) {
+    return false;
+  }
+
+  creationTime = (static_cast<uint64_t>(ftCreation.dwHighDateTime) << 32) |
+                 ftCreation.dwLowDateTime;
+  return true;
+}
+
+/**
+ * Tracks the earliest-created process in each app container seen during a
+ * walk, which is taken to be the main process of the app that the others in
+ * the container (e.g. background tasks) belong to.
+ */
+class ContainerMainProcesses {
+public:
+  /**
+   * Records the process. Returns its container's SID, or an empty string if
+   * that or its creation time couldn't be read.
+   */
+  std::u16string Add(DWORD pid, HANDLE hProcess, HANDLE hToken) {
+    std::u16string sid;
+    uint64_t creationTime;
+    if (!GetAppContainerSid(hToken, sid) ||
+        !GetProcessCreationTime(hProcess, creationTime)) {
+      return std::u16string();
+    }
+
+    auto earliest = earliestBySid.find(sid);
+    if (earliest == earliestBySid.end() ||
+        creationTime < earliest->second.first) {
+      earliestBySid[sid] = {creationTime, pid};
+    }
+
+    return sid;
+  }
 
-      CloseHandle(hProcess);
+  /**
+   * Gets the main process of the container with the given SID.
+   */
+  bool Get(const std::u16string &sid, DWORD &dwPid) const {
+    auto earliest = earliestBySid.find(sid);
+    if (earliest == earliestBySid.end()) {
+      return false;
     }
-  } while (Process32Next(hProcessSnap, &pe32));
 
-  CloseHandle(hProcessSnap);
+    dwPid = earliest->second.second;
+    return true;
+  }
+
+private:
+  std::unordered_map<std::u16string, std::pair<uint64_t, DWORD>> earliestBySid;
+};
+
+/**
+ * Reads one of the process' mitigation policies into `policy`.
+ */
+template <typename T>
+bool GetMitigationPolicy(HANDLE hProcess, PROCESS_MITIGATION_POLICY policyClass,
+                         T &policy) {
+  return GetProcessMitigationPolicy(hProcess, policyClass, &policy,
+                                    sizeof(policy)) != FALSE;
+}
+
+/**
+ * Gets whether DEP, ASLR, CFG and ACG (prohibited dynamic code) are enabled
+ * for the process. Each policy is read with its own call, and those that
+ * can't be read are left unset.
+ */
+Napi::Object GetProcessMitigations(Napi::Env env, HANDLE hProcess) {
+  auto mitigations = Napi::Object::New(env);
+
+  PROCESS_MITIGATION_DEP_POLICY dep;
+  if (GetMitigationPolicy(hProcess, ProcessDEPPolicy, dep)) {
+    mitigations.Set("dep", Napi::Boolean::New(env, dep.Enable != 0));
+  }
+
+  PROCESS_MITIGATION_ASLR_POLICY aslr;
+  if (GetMitigationPolicy(hProcess, ProcessASLRPolicy, aslr)) {
+    mitigations.Set("bottomUpAslr", Napi::Boolean::New(
+                                        env, aslr.EnableBottomUpRandomization));
+    mitigations.Set("forceRelocateImages",
+                    Napi::Boolean::New(env, aslr.EnableForceRelocateImages));
+    mitigations.Set("highEntropyAslr",
+                    Napi::Boolean::New(env, aslr.EnableHighEntropy));
+  }
+
+  PROCESS_MITIGATION_CONTROL_FLOW_GUARD_POLICY cfg;
+  if (GetMitigationPolicy(hProcess, ProcessControlFlowGuardPolicy, cfg)) {
+    mitigations.Set("cfg", Napi::Boolean::New(env, cfg.EnableControlFlowGuard));
+  }
+
+  PROCESS_MITIGATION_DYNAMIC_CODE_POLICY dynamicCode;
+  if (GetMitigationPolicy(hProcess, ProcessDynamicCodePolicy, dynamicCode)) {
+    mitigations.Set("dynamicCodeProhibited",
+                    Napi::Boolean::New(env, dynamicCode.ProhibitDynamicCode));
+  }
+
+  return mitigations;
+}
+
+/**
+ * Optional details gathered for each app container process, only when asked
+ * for. See `AppContainerInfoOptions` in index.ts.
+ */
+struct ProcessDetails {
+  bool capabilities = false;
+  bool packageFamilyName = false;
+  bool aumid = false;
+  bool isBackgroundTask = false;
+  bool moniker = false;
+  bool packageMainPid = false;
+  bool ownerSid = false;
+  bool mitigations = false;
+  bool microsoftSignedOnly = false;
+  // Set when loopback exemptions were requested.
+  const LoopbackExemptions *loopbackExemptions = NULL;
+};
+
+/**
+ * Reads a boolean option, treating anything else as false.
+ */
+bool GetBooleanOption(Napi::Object options, const char *name) {
+  auto value = options.Get(name);
+  return value.IsBoolean() && value.As<Napi::Boolean>().Value();
+}
+
+/**
+ * Reads a numeric option, falling back to `defaultValue` if it isn't a number.
+ */
+DWORD GetNumberOption(Napi::Object options, const char *name,
+                      DWORD defaultValue) {
+  auto value = options.Get(name);
+  return value.IsNumber() ? value.As<Napi::Number>().Uint32Value()
+                          : defaultValue;
+}
+
+/**
+ * Adds the app container process to `processes`, along with the requested
+ * `details`. On failure, returns false and sets `reason` to why its pipe path
+ * couldn't be resolved.
+ */
+BOOL addAppContainerProcess(Napi::Env env, Napi::Array processes,
+                            const PROCESSENTRY32 &pe32, HANDLE hProcess,
+                            HANDLE hToken, const ProcessDetails &details,
+                            SkipReason &reason) {
+  ULONG ulSessionId;
+  std::u16string objectPath;
+  if (!GetAppContainerObjectPath(hToken, ulSessionId, objectPath, reason)) {
+    return false;
+  }
+
+  // The pipe path itself is formatted in JS, see `formatPipePath`.
+  auto process = Napi::Object::New(env);
+  process.Set("pid", Napi::Number::New(env, pe32.th32ProcessID));
+  process.Set("sessionId", Napi::Number::New(env, ulSessionId));
+  process.Set("objectPath", Napi::String::New(env, objectPath.c_str()));
+  if (details.capabilities) {
+    process.Set("capabilities", GetTokenCapabilities(env, hToken));
+  }
+
+  // Not every app container is packaged, in which case this is left unset.
+  WCHAR familyName[PACKAGE_FAMILY_NAME_MAX_LENGTH + 1] = L"";
+  UINT32 familyNameLength = sizeof(familyName) / sizeof(WCHAR);
+  if (details.packageFamilyName &&
+      GetPackageFamilyName(hProcess, &familyNameLength, familyName) ==
+          ERROR_SUCCESS) {
+    auto familyNameU16 =
+        std::u16string(familyName, familyName + wcslen(familyName));
+    process.Set("packageFamilyName",
+                Napi::String::New(env, familyNameU16.c_str()));
+  }
+
+  // Only packaged processes started as an app have an AUMID. For others this
+  // fails with APPMODEL_ERROR_NO_APPLICATION, and it's left unset.
+  WCHAR aumid[APPLICATION_USER_MODEL_ID_MAX_LENGTH] = L"";
+  UINT32 aumidLength = sizeof(aumid) / sizeof(WCHAR);
+  if (details.aumid &&
+      GetApplicationUserModelId(hProcess, &aumidLength, aumid) ==
+          ERROR_SUCCESS) {
+    auto aumidU16 = std::u16string(aumid, aumid + wcslen(aumid));
+    process.Set("aumid", Napi::String::New(env, aumidU16.c_str()));
+  }
+
+  // Out-of-process background tasks of packaged apps run in a system-provided
+  // host in the app's container. Tasks that run inside the app's own process
+  // aren't detected.
+  if (details.isBackgroundTask) {
+    auto isBackgroundTask =
+        lstrcmpi(pe32.szExeFile, TEXT("backgroundTaskHost.exe")) == 0;
+    process.Set("isBackgroundTask", Napi::Boolean::New(env, isBackgroundTask));
+  }
+
+  std::u16string sid, moniker;
+  if (details.moniker && GetAppContainerSid(hToken, sid) &&
+      GetAppContainerMoniker(sid, moniker)) {
+    process.Set("moniker", Napi::String::New(env, moniker.c_str()));
+  }
+
+  // The user's SID is enough to group processes by user, without the cost of
+  // resolving it to an account name.
+  std::vector<BYTE> userBuffer;
+  std::u16string ownerSid;
+  if (details.ownerSid &&
+      GetTokenInformationBuffer(hToken, TokenUser, userBuffer) &&
+      SidToString(reinterpret_cast<TOKEN_USER *>(userBuffer.data())->User.Sid,
+                  ownerSid)) {
+    process.Set("ownerSid", Napi::String::New(env, ownerSid.c_str()));
+  }
+
+  if (details.mitigations) {
+    process.Set("mitigations", GetProcessMitigations(env, hProcess));
+  }
+
+  // Only the most locked-down processes may load nothing but Microsoft-signed
+  // images.
+  PROCESS_MITIGATION_BINARY_SIGNATURE_POLICY signature;
+  if (details.microsoftSignedOnly &&
+      GetMitigationPolicy(hProcess, ProcessSignaturePolicy, signature)) {
+    process.Set("microsoftSignedOnly",
+                Napi::Boolean::New(env, signature.MicrosoftSignedOnly));
+  }
+
+  if (details.loopbackExemptions) {
+    std::vector<BYTE> buffer;
+    auto loopbackExempt =
+        GetTokenInformationBuffer(hToken, TokenAppContainerSid, buffer) &&
+        details.loopbackExemptions->Contains(
+            reinterpret_cast<TOKEN_APPCONTAINER_INFORMATION *>(buffer.data())
+                ->TokenAppContainer);
+    process.Set("loopbackExempt", Napi::Boolean::New(env, loopbackExempt));
+  }
+
+  processes[processes.Length()] = process;
+  return true;
+}
+
+/**
+ * Classifies why a process couldn't be opened: access denied is expected for
+ * protected processes, while an invalid parameter means it already exited.
+ */
+const char *InaccessibleKind(DWORD dwError) {
+  switch (dwError) {
+  case ERROR_ACCESS_DENIED:
+    return "access_denied";
+  case ERROR_INVALID_PARAMETER:
+    return "not_found";
+  default:
+    return "other";
+  }
+}
+
+/**
+ * Gets whether the process is protected (PP or PPL), like antimalware services
+ * and LSA. Those deny the walk's access, but still grant limited query access.
+ */
+bool IsProtectedProcess(DWORD pid, bool &isProtected) {
+  ScopedHandle hProcess(
+      OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid));
+  PROCESS_PROTECTION_LEVEL_INFORMATION protection;
+  if (hProcess.get() == NULL ||
+      !GetProcessInformation(hProcess.get(), ProcessProtectionLevelInfo,
+                             &protection, sizeof(protection))) {
+    return false;
+  }
+
+  isProtected = protection.ProtectionLevel != PROTECTION_LEVEL_NONE;
+  return true;
+}
+
+Napi::Value getAppContainerProcesses(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  // Optionally also gather Low integrity processes that aren't in an app
+  // container, since some sandboxes rely on integrity levels alone. Callers
+  // may raise the threshold to see e.g. Medium integrity processes as well.
+  auto includeLowIntegrity = false;
+  DWORD dwMaxIntegrityLevel = SECURITY_MANDATORY_LOW_RID;
+  auto includeInaccessible = false;
+  auto inaccessibleProtection = false;
+  ProcessDetails details;
+  LoopbackExemptions loopbackExemptions;
+  if (info[0].IsObject()) {
+    auto options = info[0].As<Napi::Object>();
+    includeLowIntegrity = GetBooleanOption(options, "includeLowIntegrity");
+    dwMaxIntegrityLevel = GetNumberOption(options, "maxIntegrityLevel",
+                                          SECURITY_MANDATORY_LOW_RID);
+    includeInaccessible = GetBooleanOption(options, "inaccessible");
+    inaccessibleProtection = GetBooleanOption(options, "isProtected");
+    details.capabilities = GetBooleanOption(options, "capabilities");
+    details.packageFamilyName = GetBooleanOption(options, "packageFamilyName");
+    details.aumid = GetBooleanOption(options, "aumid");
+    details.isBackgroundTask = GetBooleanOption(options, "isBackgroundTask");
+    details.moniker = GetBooleanOption(options, "moniker");
+    details.packageMainPid = GetBooleanOption(options, "packageMainPid");
+    details.ownerSid = GetBooleanOption(options, "ownerSid");
+    details.mitigations = GetBooleanOption(options, "mitigations");
+    details.microsoftSignedOnly =
+        GetBooleanOption(options, "microsoftSignedOnly");
+
+    // If the exemptions can't be read, every container is reported as not
+    // exempt, so say why.
+    if (GetBooleanOption(options, "loopbackExempt")) {
+      auto dwError = loopbackExemptions.Load();
+      if (dwError != ERROR_SUCCESS) {
+        EmitJsWarning(env, "NetworkIsolationGetAppContainerConfig failed: " +
+                               std::to_string(dwError));
+      }
+      details.loopbackExemptions = &loopbackExemptions;
+    }
+  }
+
+  auto processes = Napi::Array::New(env);
+  auto skipped = Napi::Array::New(env);
+  auto lowIntegrity = Napi::Array::New(env);
+  auto inaccessible = Napi::Array::New(env);
+
+  // Main processes can only be told once every container process was seen, so
+  // remember the container of each entry in `processes` until then.
+  ContainerMainProcesses mainProcesses;
+  std::vector<std::u16string> processContainers;
+
+  bool retried;
+  auto failedCall = ForEachProcess([&](const PROCESSENTRY32 &pe32) {
+    ScopedHandle hProcess(
+        OpenProcess(WalkProcessAccess, FALSE, pe32.th32ProcessID));
+    ScopedHandle hToken;
+    if (hProcess.get() == NULL ||
+        !OpenProcessToken(hProcess.get(), TOKEN_QUERY, hToken.put())) {
+      // The idle process has PID 0 and can never be opened.
+      if (includeInaccessible && pe32.th32ProcessID != 0) {
+        auto dwError = GetLastError();
+        auto process = Napi::Object::New(env);
+        process.Set("pid", Napi::Number::New(env, pe32.th32ProcessID));
+        process.Set("kind", Napi::String::New(env, InaccessibleKind(dwError)));
+        process.Set("win32Error", Napi::Number::New(env, dwError));
+
+        // Protected processes are expected to deny access, so tell them apart
+        // from processes that might be readable with more privileges.
+        bool isProtected;
+        if (inaccessibleProtection && dwError == ERROR_ACCESS_DENIED &&
+            IsProtectedProcess(pe32.th32ProcessID, isProtected)) {
+          process.Set("isProtected", Napi::Boolean::New(env, isProtected));
+        }
+        inaccessible[inaccessible.Length()] = process;
+      }
+      return true;
+    }
+
+    SkipReason reason;
+    DWORD dwIntegrityLevel;
+    if (IsAppContainerToken(env, hToken.get())) {
+      if (!addAppContainerProcess(env, processes, pe32, hProcess.get(),
+                                  hToken.get(), details, reason)) {
+        auto skip = Napi::Object::New(env);
+        skip.Set("pid", Napi::Number::New(env, pe32.th32ProcessID));
+        skip.Set("reason", Napi::String::New(env, reason.message));
+        skip.Set("win32Error", Napi::Number::New(env, reason.dwError));
+        skipped[skipped.Length()] = skip;
+        if (reason.warn) {
+          EmitJsWarning(env, reason.message);
+        }
+      } else if (details.packageMainPid) {
+        processContainers.push_back(mainProcesses.Add(
+            pe32.th32ProcessID, hProcess.get(), hToken.get()));
+      }
+    } else if (includeLowIntegrity &&
+               GetIntegrityLevel(hToken.get(), dwIntegrityLevel) &&
+               dwIntegrityLevel <= dwMaxIntegrityLevel) {
+      auto process = Napi::Object::New(env);
+      process.Set("pid", Napi::Number::New(env, pe32.th32ProcessID));
+      process.Set("integrityLevel", Napi::Number::New(env, dwIntegrityLevel));
+      lowIntegrity[lowIntegrity.Length()] = process;
+    }
+
+    return true;
+  }, &retried);
+
+  if (failedCall) {
+    ThrowJsError(env, failedCall);
+    return env.Null();
+  }
+
+  for (uint32_t i = 0; i < processContainers.size(); i++) {
+    DWORD dwMainPid;
+    if (mainProcesses.Get(processContainers[i], dwMainPid)) {
+      Napi::Value process = processes[i];
+      process.As<Napi::Object>().Set("packageMainPid",
+                                     Napi::Number::New(env, dwMainPid));
+    }
+  }
+
+  auto result = Napi::Object::New(env);
+  result.Set("processes", processes);
+  result.Set("skipped", skipped);
+  result.Set("lowIntegrity", lowIntegrity);
+  result.Set("inaccessible", inaccessible);
+  result.Set("retried", Napi::Boolean::New(env, retried));
+  return result;
+}
+
+Napi::Value isCurrentProcessAppContainer(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  ScopedHandle hToken;
+  if (!OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, hToken.put())) {
+    ThrowJsError(env, "OpenProcessToken");
+    return env.Null();
+  }
+
+  return Napi::Boolean::New(env, IsAppContainerToken(env, hToken.get()));
+}
+
+Napi::Value getCurrentProcessAppContainerSid(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  ScopedHandle hToken;
+  if (!OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, hToken.put())) {
+    ThrowJsError(env, "OpenProcessToken");
+    return env.Null();
+  }
+
+  std::u16string sid;
+  if (!GetAppContainerSid(hToken.get(), sid)) {
+    return env.Null();
+  }
+
+  return Napi::String::New(env, sid.c_str());
+}
+
+Napi::Value whichAreAppContainers(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsArray()) {
+    Napi::TypeError::New(env, "Expected (pids: number[])")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  auto pids = info[0].As<Napi::Array>();
+  auto appContainerPids = Napi::Array::New(env);
+  for (uint32_t i = 0; i < pids.Length(); i++) {
+    Napi::Value value = pids[i];
+    if (!value.IsNumber()) {
+      continue;
+    }
+
+    // Processes we can't open are simply not reported, as in the full walk.
+    auto pid = value.As<Napi::Number>().Uint32Value();
+    ScopedHandle hToken;
+    if (OpenProcessTokenById(pid, hToken)) {
+      continue;
+    }
+
+    if (IsAppContainerToken(env, hToken.get())) {
+      appContainerPids[appContainerPids.Length()] = Napi::Number::New(env, pid);
+    }
+  }
+
+  return appContainerPids;
+}
+
+Napi::Value getNamedObjectPathForSid(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsString()) {
+    Napi::TypeError::New(env, "Expected (sid: string)")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  auto sid = info[0].As<Napi::String>().Utf16Value();
+
+  PSID pSid;
+  if (!ConvertStringSidToSidW(reinterpret_cast<LPCWSTR>(sid.c_str()),
+                              &pSid)) {
+    ThrowJsError(env, "ConvertStringSidToSidW");
+    return env.Null();
+  }
+
+  WCHAR ObjectPath[1024] = L"";
+  ULONG ulReturnLength;
+  auto ok = GetAppContainerNamedObjectPath(NULL, pSid,
+                                           sizeof(ObjectPath) / sizeof(WCHAR),
+                                           ObjectPath, &ulReturnLength);
+  LocalFree(pSid);
+
+  if (!ok) {
+    return env.Null();
+  }
+
+  auto objectPathU16 = DecodeObjectPath(
+      ObjectPath, sizeof(ObjectPath) / sizeof(WCHAR), ulReturnLength);
+  if (objectPathU16.empty()) {
+    return env.Null();
+  }
+
+  return Napi::String::New(env, objectPathU16.c_str());
+}
+
+/**
+ * Exposes `DecodeObjectPath` so the tests can feed it buffers the API rarely
+ * produces, such as ones without a null terminator.
+ */
+Napi::Value decodeObjectPath(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsTypedArray() ||
+      info[0].As<Napi::TypedArray>().TypedArrayType() != napi_uint16_array ||
+      !info[1].IsNumber()) {
+    Napi::TypeError::New(env,
+                         "Expected (buffer: Uint16Array, returnLength: number)")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  auto buffer = info[0].As<Napi::TypedArrayOf<uint16_t>>();
+  auto objectPath = DecodeObjectPath(
+      reinterpret_cast<const WCHAR *>(buffer.Data()),
+      static_cast<ULONG>(buffer.ElementLength()),
+      info[1].As<Napi::Number>().Uint32Value());
+  return Napi::String::New(env, objectPath.c_str());
+}
+
+Napi::Value getProcessLogonId(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsNumber()) {
+    Napi::TypeError::New(env, "Expected (pid: number)")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  ScopedHandle hToken;
+  auto failedCall =
+      OpenProcessTokenById(info[0].As<Napi::Number>().Uint32Value(), hToken);
+  if (failedCall) {
+    ThrowJsError(env, failedCall);
+    return env.Null();
+  }
+
+  TOKEN_STATISTICS stats;
+  DWORD dwReturnLength;
+  if (!GetTokenInformation(hToken.get(), TokenStatistics, &stats,
+                           sizeof(stats), &dwReturnLength)) {
+    ThrowJsError(env, "GetTokenInformation(TokenStatistics)");
+    return env.Null();
+  }
+
+  auto luid = (static_cast<uint64_t>(stats.AuthenticationId.HighPart) << 32) |
+              stats.AuthenticationId.LowPart;
+  char luidHex[19];
+  snprintf(luidHex, sizeof(luidHex), "0x%llx",
+           static_cast<unsigned long long>(luid));
+  return Napi::String::New(env, luidHex);
+}
+
+Napi::Value queryProcessTokenInfo(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsNumber() || !info[1].IsNumber() || !info[2].IsNumber()) {
+    Napi::TypeError::New(
+        env, "Expected (pid: number, infoClass: number, maxBytes: number)")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  auto pid = info[0].As<Napi::Number>().Uint32Value();
+  auto infoClass = static_cast<TOKEN_INFORMATION_CLASS>(
+      info[1].As<Napi::Number>().Uint32Value());
+  auto maxBytes = info[2].As<Napi::Number>().Uint32Value();
+
+  // TokenLinkedToken hands back a new token handle, which the caller would
+  // have no way to close.
+  if (infoClass == TokenLinkedToken) {
+    Napi::RangeError::New(env, "TokenLinkedToken returns a handle and isn't "
+                               "supported")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  if (maxBytes == 0 || maxBytes > MaxTokenInfoBytes) {
+    Napi::RangeError::New(env, "maxBytes must be between 1 and " +
+                                   std::to_string(MaxTokenInfoBytes))
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  ScopedHandle hToken;
+  auto failedCall = OpenProcessTokenById(pid, hToken);
+  if (failedCall) {
+    ThrowJsError(env, failedCall);
+    return env.Null();
+  }
+
+  std::vector<BYTE> buffer(maxBytes);
+  DWORD dwReturnLength;
+  if (!GetTokenInformation(hToken.get(), infoClass, buffer.data(), maxBytes,
+                           &dwReturnLength)) {
+    ThrowJsError(env, "GetTokenInformation");
+    return env.Null();
+  }
+
+  return Napi::Buffer<BYTE>::Copy(env, buffer.data(), dwReturnLength);
+}
+
+Napi::Value getCurrentSessionId(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  DWORD dwSessionId;
+  if (!ProcessIdToSessionId(GetCurrentProcessId(), &dwSessionId)) {
+    ThrowJsError(env, "ProcessIdToSessionId");
+    return env.Null();
+  }
+
+  return Napi::Number::New(env, dwSessionId);
+}
+
+Napi::Value getOpenSnapshotCount(const Napi::CallbackInfo &info) {
+  return Napi::Number::New(info.Env(), openSnapshotCount.load());
+}
+
+Napi::Value getOpenHandleCount(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  DWORD dwHandleCount;
+  if (!GetProcessHandleCount(GetCurrentProcess(), &dwHandleCount)) {
+    ThrowJsError(env, "GetProcessHandleCount");
+    return env.Null();
+  }
+
+  return Napi::Number::New(env, dwHandleCount);
+}
+
+Napi::Value getProcessCount(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  uint32_t count = 0;
+  auto failedCall = ForEachProcess([&](const PROCESSENTRY32 &) {
+    count++;
+    return true;
+  });
+
+  if (failedCall) {
+    ThrowJsError(env, failedCall);
+    return env.Null();
+  }
+
+  return Napi::Number::New(env, count);
+}
+
+Napi::Value getProcessesCreatedSince(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsBigInt()) {
+    Napi::TypeError::New(env, "Expected (since: bigint)")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  bool lossless;
+  auto since = info[0].As<Napi::BigInt>().Uint64Value(&lossless);
+  if (!lossless) {
+    Napi::RangeError::New(env, "since must be a FILETIME")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  // Processes that can't be opened have no known creation time, so they're
+  // left out rather than guessed at.
+  auto pids = Napi::Array::New(env);
+  auto failedCall = ForEachProcess([&](const PROCESSENTRY32 &pe32) {
+    ScopedHandle hProcess(OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE,
+                                      pe32.th32ProcessID));
+    uint64_t creationTime;
+    if (hProcess.get() != NULL &&
+        GetProcessCreationTime(hProcess.get(), creationTime) &&
+        creationTime >= since) {
+      pids[pids.Length()] = Napi::Number::New(env, pe32.th32ProcessID);
+    }
+    return true;
+  });
+
+  if (failedCall) {
+    ThrowJsError(env, failedCall);
+    return env.Null();
+  }
+
+  return pids;
+}
+
+/**
+ * Base for workers that settle a promise. Failures reject it with the Win32
+ * error code attached as `win32Error`, like errors thrown by `ThrowJsError`.
+ */
+class PromiseWorker : public Napi::AsyncWorker {
+public:
+  explicit PromiseWorker(Napi::Env env)
+      : Napi::AsyncWorker(env), deferred(Napi::Promise::Deferred::New(env)) {}
+
+  Napi::Promise GetPromise() { return deferred.Promise(); }
+
+  void OnError(const Napi::Error &err) override {
+    auto error = err.Value();
+    error.Set("win32Error", Napi::Number::New(Env(), dwError));
+    deferred.Reject(error);
+  }
+
+protected:
+  /**
+   * Fails the worker with the last Win32 error. Only call from `Execute`.
+   */
+  void SetLastWin32Error(const char *msg) {
+    dwError = GetLastError();
+    SetError(FormatLastError(msg));
+  }
+
+  Napi::Promise::Deferred deferred;
+
+private:
+  DWORD dwError = ERROR_SUCCESS;
+};
+
+/**
+ * Returns a promise rejected with the given error, for functions that report
+ * all failures through the promise they return.
+ */
+Napi::Promise RejectedPromise(Napi::Env env, const Napi::Error &err) {
+  auto deferred = Napi::Promise::Deferred::New(env);
+  deferred.Reject(err.Value());
+  return deferred.Promise();
+}
+
+/**
+ * Longest timeout the wait functions accept. Each wait holds one of the few
+ * libuv threadpool threads, which the rest of the process shares, until it
+ * finishes, so it mustn't be unbounded (INFINITE) or effectively so.
+ */
+const DWORD MaxWaitTimeoutMs = 60 * 60 * 1000;
+
+/**
+ * Reads a wait timeout in milliseconds. Returns false if it's negative, not a
+ * number, or longer than `MaxWaitTimeoutMs`.
+ */
+bool GetWaitTimeout(Napi::Value value, DWORD &dwTimeout) {
+  auto timeoutMs = value.As<Napi::Number>().DoubleValue();
+  if (!(timeoutMs >= 0 && timeoutMs <= MaxWaitTimeoutMs)) {
+    return false;
+  }
+
+  dwTimeout = static_cast<DWORD>(timeoutMs);
+  return true;
+}
+
+/**
+ * Creates the error for a timeout that `GetWaitTimeout` rejected.
+ */
+Napi::Error CreateWaitTimeoutError(Napi::Env env) {
+  return Napi::RangeError::New(env, "timeoutMs must be between 0 and " +
+                                        std::to_string(MaxWaitTimeoutMs));
+}
+
+/**
+ * Waits on the libuv threadpool for a process handle to become signaled.
+ * Takes ownership of the handle.
+ */
+class WaitForProcessExitWorker : public PromiseWorker {
+public:
+  WaitForProcessExitWorker(Napi::Env env, HANDLE hProcess, DWORD dwTimeout)
+      : PromiseWorker(env), hProcess(hProcess), dwTimeout(dwTimeout) {}
+
+  void Execute() override {
+    dwResult = WaitForSingleObject(hProcess.get(), dwTimeout);
+    if (dwResult == WAIT_FAILED) {
+      SetLastWin32Error("WaitForSingleObject");
+    }
+  }
+
+  void OnOK() override {
+    deferred.Resolve(Napi::Boolean::New(Env(), dwResult == WAIT_OBJECT_0));
+  }
+
+private:
+  ScopedHandle hProcess;
+  DWORD dwTimeout;
+  DWORD dwResult = WAIT_FAILED;
+};
+
+Napi::Value waitForProcessExit(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  // This returns a promise, so failures reject it rather than throwing.
+  if (!info[0].IsNumber() || !info[1].IsNumber()) {
+    return RejectedPromise(
+        env,
+        Napi::TypeError::New(env, "Expected (pid: number, timeoutMs: number)"));
+  }
+
+  auto pid = info[0].As<Napi::Number>().Uint32Value();
+  DWORD dwTimeout;
+  if (!GetWaitTimeout(info[1], dwTimeout)) {
+    return RejectedPromise(env, CreateWaitTimeoutError(env));
+  }
+
+  auto hProcess = OpenProcess(SYNCHRONIZE, FALSE, pid);
+  if (hProcess == NULL) {
+    // A PID that doesn't exist (anymore) has, for our purposes, exited.
+    if (GetLastError() == ERROR_INVALID_PARAMETER) {
+      auto deferred = Napi::Promise::Deferred::New(env);
+      deferred.Resolve(Napi::Boolean::New(env, true));
+      return deferred.Promise();
+    }
+
+    return RejectedPromise(env, CreateJsError(env, "OpenProcess"));
+  }
+
+  auto worker = new WaitForProcessExitWorker(env, hProcess, dwTimeout);
+  auto promise = worker->GetPromise();
+  worker->Queue();
+  return promise;
+}
+
+/**
+ * Polls process snapshots on the libuv threadpool until a process running in
+ * the given app container appears. Takes ownership of the SID.
+ */
+class WaitForAppContainerProcessWorker : public PromiseWorker {
+public:
+  WaitForAppContainerProcessWorker(Napi::Env env, PSID pSid, DWORD dwTimeout)
+      : PromiseWorker(env), pSid(pSid), dwTimeout(dwTimeout) {}
+
+  ~WaitForAppContainerProcessWorker() { LocalFree(pSid); }
+
+  void Execute() override {
+    auto start = GetTickCount64();
+
+    while (true) {
+      auto failedCall = ForEachProcess([&](const PROCESSENTRY32 &pe32) {
+        ScopedHandle hProcess(
+            OpenProcess(WalkProcessAccess, FALSE, pe32.th32ProcessID));
+        ScopedHandle hToken;
+        if (hProcess.get() == NULL ||
+            !OpenProcessToken(hProcess.get(), TOKEN_QUERY, hToken.put())) {
+          return true;
+        }
+
+        std::vector<BYTE> buffer;
+        SkipReason reason;
+        if (GetTokenInformationBuffer(hToken.get(), TokenAppContainerSid,
+                                      buffer) &&
+            IsTargetContainer(buffer) &&
+            GetAppContainerObjectPath(hToken.get(), ulSessionId, objectPath,
+                                      reason)) {
+          found = true;
+          dwPid = pe32.th32ProcessID;
+        }
+
+        return !found;
+      });
+
+      if (failedCall) {
+        SetLastWin32Error(failedCall);
+        return;
+      }
+
+      if (found || GetTickCount64() - start >= dwTimeout) {
+        return;
+      }
+
+      Sleep(PollIntervalMs);
+    }
+  }
+
+  void OnOK() override {
+    Napi::Env env = Env();
+    if (!found) {
+      deferred.Resolve(env.Null());
+      return;
+    }
+
+    auto process = Napi::Object::New(env);
+    process.Set("pid", Napi::Number::New(env, dwPid));
+    process.Set("sessionId", Napi::Number::New(env, ulSessionId));
+    process.Set("objectPath", Napi::String::New(env, objectPath.c_str()));
+    deferred.Resolve(process);
+  }
+
+private:
+  static const DWORD PollIntervalMs = 100;
+
+  bool IsTargetContainer(std::vector<BYTE> &appContainerSidBuffer) {
+    auto pInfo = reinterpret_cast<TOKEN_APPCONTAINER_INFORMATION *>(
+        appContainerSidBuffer.data());
+    return pInfo->TokenAppContainer != NULL &&
+           EqualSid(pInfo->TokenAppContainer, pSid);
+  }
+
+  PSID pSid;
+  DWORD dwTimeout;
+  bool found = false;
+  DWORD dwPid = 0;
+  ULONG ulSessionId = 0;
+  std::u16string objectPath;
+};
+
+Napi::Value waitForAppContainerProcess(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  // This returns a promise, so failures reject it rather than throwing.
+  if (!info[0].IsString() || !info[1].IsNumber()) {
+    return RejectedPromise(
+        env,
+        Napi::TypeError::New(env, "Expected (sid: string, timeoutMs: number)"));
+  }
+
+  auto sid = info[0].As<Napi::String>().Utf16Value();
+  DWORD dwTimeout;
+  if (!GetWaitTimeout(info[1], dwTimeout)) {
+    return RejectedPromise(env, CreateWaitTimeoutError(env));
+  }
+
+  PSID pSid;
+  if (!ConvertStringSidToSidW(reinterpret_cast<LPCWSTR>(sid.c_str()),
+                              &pSid)) {
+    return RejectedPromise(env, CreateJsError(env, "ConvertStringSidToSidW"));
+  }
+
+  auto worker = new WaitForAppContainerProcessWorker(env, pSid, dwTimeout);
+  auto promise = worker->GetPromise();
+  worker->Queue();
+  return promise;
+}
+
+Napi::Value getProcessExitCode(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsNumber()) {
+    Napi::TypeError::New(env, "Expected (pid: number)")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  auto pid = info[0].As<Napi::Number>().Uint32Value();
+  ScopedHandle hProcess(
+      OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid));
+  if (hProcess.get() == NULL) {
+    ThrowJsError(env, "OpenProcess");
+    return env.Null();
+  }
+
+  DWORD dwExitCode;
+  if (!GetExitCodeProcess(hProcess.get(), &dwExitCode)) {
+    ThrowJsError(env, "GetExitCodeProcess");
+    return env.Null();
+  }
+
+  if (dwExitCode == STILL_ACTIVE) {
+    return env.Null();
+  }
+
+  // Exit codes are often NTSTATUS values, which read better as signed ints.
+  return Napi::Number::New(env, static_cast<int32_t>(dwExitCode));
+}
+
+Napi::Value getProcessCreationTime(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsNumber()) {
+    Napi::TypeError::New(env, "Expected (pid: number)")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  ScopedHandle hProcess(OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE,
+                                    info[0].As<Napi::Number>().Uint32Value()));
+  if (hProcess.get() == NULL) {
+    ThrowJsError(env, "OpenProcess");
+    return env.Null();
+  }
+
+  uint64_t creationTime;
+  if (!GetProcessCreationTime(hProcess.get(), creationTime)) {
+    ThrowJsError(env, "GetProcessTimes");
+    return env.Null();
+  }
+
+  return Napi::BigInt::New(env, creationTime);
+}
+
+Napi::Value terminateProcess(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsNumber() || !info[1].IsNumber() || !info[2].IsBigInt()) {
+    Napi::TypeError::New(env, "Expected (pid: number, exitCode: number, "
+                              "expectedCreationTime: bigint)")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  auto pid = info[0].As<Napi::Number>().Uint32Value();
+  auto exitCode = info[1].As<Napi::Number>().Uint32Value();
+  bool lossless;
+  auto expectedCreationTime = info[2].As<Napi::BigInt>().Uint64Value(&lossless);
+
+  ScopedHandle hProcess(OpenProcess(
+      PROCESS_TERMINATE | PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid));
+  if (hProcess.get() == NULL) {
+    // The process is already gone, so there's nothing to terminate.
+    if (GetLastError() == ERROR_INVALID_PARAMETER) {
+      return Napi::Boolean::New(env, false);
+    }
+
+    ThrowJsError(env, "OpenProcess");
+    return env.Null();
+  }
+
+  // The PID may have been reused by another process since the caller looked
+  // it up, in which case that process must be left alone.
+  uint64_t creationTime;
+  if (!GetProcessCreationTime(hProcess.get(), creationTime)) {
+    ThrowJsError(env, "GetProcessTimes");
+    return env.Null();
+  }
+
+  if (!lossless || creationTime != expectedCreationTime) {
+    return Napi::Boolean::New(env, false);
+  }
+
+  if (!TerminateProcess(hProcess.get(), exitCode)) {
+    ThrowJsError(env, "TerminateProcess");
+    return env.Null();
+  }
+
+  return Napi::Boolean::New(env, true);
+}
+
+/**
+ * Reads the SystemProcessInformation list into `buffer`. Unlike a Toolhelp
+ * snapshot, each entry carries the process' session ID.
+ */
+NTSTATUS QuerySystemProcessInformation(std::vector<BYTE> &buffer) {
+  // The process list can grow between calls, so leave some headroom when
+  // retrying with the size we were told.
+  buffer.resize(256 * 1024);
+  ULONG ulReturnLength;
+  NTSTATUS status;
+  while ((status = NtQuerySystemInformation(
+              SystemProcessInformation, buffer.data(),
+              static_cast<ULONG>(buffer.size()), &ulReturnLength)) ==
+         STATUS_INFO_LENGTH_MISMATCH) {
+    buffer.resize(ulReturnLength + 64 * 1024);
+  }
+  return status;
+}
+
+Napi::Value isProcessFrozen(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  if (!info[0].IsNumber()) {
+    Napi::TypeError::New(env, "Expected (pid: number)")
+        .ThrowAsJavaScriptException();
+    return env.Null();
+  }
+
+  auto pid = info[0].As<Napi::Number>().Uint32Value();
+
+  std::vector<BYTE> buffer;
+  if (QuerySystemProcessInformation(buffer) < 0) {
+    return Napi::Boolean::New(env, false);
+  }
+
+  // A process is frozen (e.g. suspended by PLM) when every one of its threads
+  // is waiting because it was suspended.
+  auto spi = reinterpret_cast<SYSTEM_PROCESS_INFORMATION *>(buffer.data());
+  while (true) {
+    if (reinterpret_cast<ULONG_PTR>(spi->UniqueProcessId) == pid) {
+      auto threads = reinterpret_cast<SYSTEM_THREAD_INFORMATION *>(spi + 1);
+      auto frozen = spi->NumberOfThreads > 0;
+      for (ULONG i = 0; frozen && i < spi->NumberOfThreads; i++) {
+        frozen = threads[i].ThreadState == ThreadStateWaiting &&
+                 threads[i].WaitReason == WaitReasonSuspended;
+      }
+      return Napi::Boolean::New(env, frozen);
+    }
+
+    if (spi->NextEntryOffset == 0) {
+      return Napi::Boolean::New(env, false);
+    }
+
+    spi = reinterpret_cast<SYSTEM_PROCESS_INFORMATION *>(
+        reinterpret_cast<BYTE *>(spi) + spi->NextEntryOffset);
+  }
+}
+
+/**
+ * Number of processes in other sessions that `probeEnumerationCapabilities`
+ * tries to open before giving up.
+ */
+const int MaxProbeCandidates = 4;
+
+/**
+ * Returns whether the token has the privilege enabled, not merely present.
+ */
+bool IsPrivilegeEnabled(HANDLE hToken, LPCWSTR privilegeName) {
+  LUID luid;
+  std::vector<BYTE> buffer;
+  if (!LookupPrivilegeValueW(NULL, privilegeName, &luid) ||
+      !GetTokenInformationBuffer(hToken, TokenPrivileges, buffer)) {
+    return false;
+  }
+
+  auto pPrivileges = reinterpret_cast<TOKEN_PRIVILEGES *>(buffer.data());
+  for (DWORD i = 0; i < pPrivileges->PrivilegeCount; i++) {
+    auto &privilege = pPrivileges->Privileges[i];
+    if (privilege.Luid.LowPart == luid.LowPart &&
+        privilege.Luid.HighPart == luid.HighPart) {
+      return (privilege.Attributes & SE_PRIVILEGE_ENABLED) != 0;
+    }
+  }
+
+  return false;
+}
+
+Napi::Value probeEnumerationCapabilities(const Napi::CallbackInfo &info) {
+  Napi::Env env = info.Env();
+
+  ScopedHandle hToken;
+  if (!OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, hToken.put())) {
+    ThrowJsError(env, "OpenProcessToken");
+    return env.Null();
+  }
+
+  TOKEN_ELEVATION elevation;
+  DWORD dwReturnLength;
+  auto isElevated = GetTokenInformation(hToken.get(), TokenElevation,
+                                        &elevation, sizeof(elevation),
+                                        &dwReturnLength) &&
+                    elevation.TokenIsElevated != 0;
+
+  DWORD dwCurrentSessionId;
+  if (!ProcessIdToSessionId(GetCurrentProcessId(), &dwCurrentSessionId)) {
+    ThrowJsError(env, "ProcessIdToSessionId");
+    return env.Null();
+  }
+
+  // Enumeration needs to open other users' processes and then their tokens,
+  // so try the same, with the same access, on a few processes outside our
+  // session. Trying them all would cost as much as the enumeration itself.
+  // Their sessions come from the system process list, since looking them up
+  // by PID would need the very access being probed.
+  std::vector<BYTE> buffer;
+  auto status = QuerySystemProcessInformation(buffer);
+  if (status < 0) {
+    SetLastError(RtlNtStatusToDosError(status));
+    ThrowJsError(env, "NtQuerySystemInformation");
+    return env.Null();
+  }
+
+  auto canOpenOtherSession = false;
+  auto sampleTokenReadable = false;
+  auto candidates = 0;
+  auto spi = reinterpret_cast<SYSTEM_PROCESS_INFORMATION *>(buffer.data());
+  while (!sampleTokenReadable && candidates < MaxProbeCandidates) {
+    auto pid = static_cast<DWORD>(
+        reinterpret_cast<ULONG_PTR>(spi->UniqueProcessId));
+    if (pid != 0 && spi->SessionId != dwCurrentSessionId) {
+      // A process we fail to open still counts, so that a probe without
+      // access stops as early as one with it.
+      candidates++;
+      ScopedHandle hProcess(OpenProcess(WalkProcessAccess, FALSE, pid));
+      if (hProcess.get() != NULL) {
+        canOpenOtherSession = true;
+
+        ScopedHandle hProcessToken;
+        sampleTokenReadable = OpenProcessToken(hProcess.get(), TOKEN_QUERY,
+                                               hProcessToken.put()) != FALSE;
+      }
+    }
+
+    if (spi->NextEntryOffset == 0) {
+      break;
+    }
+
+    spi = reinterpret_cast<SYSTEM_PROCESS_INFORMATION *>(
+        reinterpret_cast<BYTE *>(spi) + spi->NextEntryOffset);
+  }
 
-  return tokens;
+  auto result = Napi::Object::New(env);
+  auto hasDebugPrivilege =
+      IsPrivilegeEnabled(hToken.get(), L"SeDebugPrivilege");
+  result.Set("isElevated", Napi::Boolean::New(env, isElevated));
+  result.Set("hasDebugPrivilege", Napi::Boolean::New(env, hasDebugPrivilege));
+  result.Set("canOpenOtherSession",
+             Napi::Boolean::New(env, canOpenOtherSession));
+  result.Set("sampleTokenReadable",
+             Napi::Boolean::New(env, sampleTokenReadable));
+  return result;
 }
 
 Napi::Object Init(Napi::Env env, Napi::Object exports) {
-  exports.Set(Napi::String::New(env, "getAppContainerProcessTokens"),
-              Napi::Function::New(env, getAppContainerProcessTokens));
+  exports.Set(Napi::String::New(env, "getAppContainerProcesses"),
+              Napi::Function::New(env, getAppContainerProcesses));
+  exports.Set(Napi::String::New(env, "isCurrentProcessAppContainer"),
+              Napi::Function::New(env, isCurrentProcessAppContainer));
+  exports.Set(Napi::String::New(env, "getCurrentProcessAppContainerSid"),
+              Napi::Function::New(env, getCurrentProcessAppContainerSid));
+  exports.Set(Napi::String::New(env, "whichAreAppContainers"),
+              Napi::Function::New(env, whichAreAppContainers));
+  exports.Set(Napi::String::New(env, "getNamedObjectPathForSid"),
+              Napi::Function::New(env, getNamedObjectPathForSid));
+  exports.Set(Napi::String::New(env, "decodeObjectPath"),
+              Napi::Function::New(env, decodeObjectPath));
+  exports.Set(Napi::String::New(env, "getProcessLogonId"),
+              Napi::Function::New(env, getProcessLogonId));
+  exports.Set(Napi::String::New(env, "queryProcessTokenInfo"),
+              Napi::Function::New(env, queryProcessTokenInfo));
+  exports.Set(Napi::String::New(env, "getCurrentSessionId"),
+              Napi::Function::New(env, getCurrentSessionId));
+  exports.Set(Napi::String::New(env, "getOpenSnapshotCount"),
+              Napi::Function::New(env, getOpenSnapshotCount));
+  exports.Set(Napi::String::New(env, "getOpenHandleCount"),
+              Napi::Function::New(env, getOpenHandleCount));
+  exports.Set(Napi::String::New(env, "getProcessCount"),
+              Napi::Function::New(env, getProcessCount));
+  exports.Set(Napi::String::New(env, "getProcessesCreatedSince"),
+              Napi::Function::New(env, getProcessesCreatedSince));
+  exports.Set(Napi::String::New(env, "waitForProcessExit"),
+              Napi::Function::New(env, waitForProcessExit));
+  exports.Set(Napi::String::New(env, "waitForAppContainerProcess"),
+              Napi::Function::New(env, waitForAppContainerProcess));
+  exports.Set(Napi::String::New(env, "getProcessExitCode"),
+              Napi::Function::New(env, getProcessExitCode));
+  exports.Set(Napi::String::New(env, "getProcessCreationTime"),
+              Napi::Function::New(env, getProcessCreationTime));
+  exports.Set(Napi::String::New(env, "terminateProcess"),
+              Napi::Function::New(env, terminateProcess));
+  exports.Set(Napi::String::New(env, "isProcessFrozen"),
+              Napi::Function::New(env, isProcessFrozen));
+  exports.Set(Napi::String::New(env, "probeEnumerationCapabilities"),
+              Napi::Function::New(env, probeEnumerationCapabilities));
   return exports;
 }
 